@@ -1,88 +1,92 @@
 use core::fmt;
+use core::hash::{Hash, Hasher};
 use core::nonzero::NonZero;
 use core::mem::{size_of, transmute};
+use std::sync::{Mutex, Once, ONCE_INIT};
 use libc;
 use ffi;
 use python::{Python, PythonObject, PythonObjectWithCheckedDowncast, PythonObjectWithTypeObject, PythonObjectDowncastError, ToPythonPointer};
 use objects::PyType;
 use err::{PyErr, PyResult};
 
+/// An owned reference to a Python object.
+///
+/// Unlike earlier versions of this type, `PyObject` no longer borrows the GIL
+/// lifetime: it stores nothing but the underlying pointer, so it can be kept inside
+/// ordinary Rust structs, collections, or anything else that must outlive a single
+/// GIL acquisition. Operations that touch the interpreter (casts, type lookups, ...)
+/// take an explicit `Python<'p>` token instead of relying on one stashed at
+/// construction time.
 #[unsafe_no_drop_flag]
-pub struct PyObject<'p> {
-    // PyObject<'p> owns one reference to the *PyObject
+pub struct PyObject {
+    // PyObject owns one reference to the *PyObject
     // ptr is not null (except possibly due to #[unsafe_no_drop_flag])
-    ptr: NonZero<*mut ffi::PyObject>,
-    py : Python<'p>
+    ptr: NonZero<*mut ffi::PyObject>
 }
 
 #[unsafe_destructor]
-impl <'p> Drop for PyObject<'p> {
+impl Drop for PyObject {
     fn drop(&mut self) {
         // TODO: change from Py_XDECREF to Py_DECREF when #[unsafe_no_drop_flag] disappears
-        unsafe { ffi::Py_XDECREF(*self.ptr); }
-    }
-}
-
-impl <'p> Clone for PyObject<'p> {
-    #[inline]
-    fn clone(&self) -> PyObject<'p> {
-        unsafe { ffi::Py_INCREF(*self.ptr) };
-        PyObject { ptr: self.ptr, py: self.py }
+        unsafe {
+            // We no longer have a Python<'p> token proving the GIL is already held,
+            // so reacquire it here before touching the refcount. Python::acquire_gil()
+            // is safe to call even when this thread already holds the GIL.
+            // TODO: once there's a cheap way to check whether this thread already
+            // holds the GIL, skip the reacquire in that (common) case.
+            let _gil_guard = Python::acquire_gil();
+            ffi::Py_XDECREF(*self.ptr);
+        }
     }
 }
 
-impl <'p> PythonObject<'p> for PyObject<'p> {
+impl PythonObject for PyObject {
     #[inline]
-    fn as_object<'a>(&'a self) -> &'a PyObject<'p> {
+    fn as_object<'a>(&'a self) -> &'a PyObject {
         self
     }
-    
+
     #[inline]
-    fn into_object(self) -> PyObject<'p> {
+    fn into_object(self) -> PyObject {
         self
     }
-    
+
     #[inline]
-    fn unchecked_downcast_from(o: PyObject<'p>) -> PyObject<'p> {
+    fn unchecked_downcast_from(o: PyObject) -> PyObject {
         o
     }
-    
+
     #[inline]
-    fn unchecked_downcast_borrow_from<'a>(o: &'a PyObject<'p>) -> &'a PyObject<'p> {
+    fn unchecked_downcast_borrow_from<'a>(o: &'a PyObject) -> &'a PyObject {
         o
     }
-    
-    #[inline]
-    fn python(&self) -> Python<'p> {
-        self.py
-    }
 }
 
-impl <'p> PythonObjectWithCheckedDowncast<'p> for PyObject<'p> {
+impl PythonObjectWithCheckedDowncast for PyObject {
     #[inline]
-    fn downcast_from(obj: PyObject<'p>) -> Result<PyObject<'p>, PythonObjectDowncastError<'p>> {
+    fn downcast_from(obj: PyObject) -> Result<PyObject, PythonObjectDowncastError> {
         Ok(obj)
     }
-    
+
     #[inline]
-    fn downcast_borrow_from<'a>(obj: &'a PyObject<'p>) -> Result<&'a PyObject<'p>, PythonObjectDowncastError<'p>> {
+    fn downcast_borrow_from<'a>(obj: &'a PyObject) -> Result<&'a PyObject, PythonObjectDowncastError> {
         Ok(obj)
     }
 }
 
-impl <'p> PythonObjectWithTypeObject<'p> for PyObject<'p> {
+impl <'p> PythonObjectWithTypeObject<'p> for PyObject {
     #[inline]
     fn type_object(py: Python<'p>, _ : Option<&Self>) -> PyType<'p> {
         unsafe { PyType::from_type_ptr(py, &mut ffi::PyBaseObject_Type) }
     }
 }
 
-impl <'p> ToPythonPointer for PyObject<'p> {
+impl ToPythonPointer for PyObject {
     #[inline]
     fn as_ptr(&self) -> *mut ffi::PyObject {
         *self.ptr
     }
-    
+
     #[inline]
     fn steal_ptr(self) -> *mut ffi::PyObject {
         let ptr = *self.ptr;
@@ -91,64 +95,98 @@ impl <'p> ToPythonPointer for PyObject<'p> {
     }
 }
 
+/// The error returned by `PyObject::cast_into` on a failed downcast.
+///
+/// `PythonObjectDowncastError` on its own doesn't hold on to the object that failed
+/// to downcast; `instance` carries it back, so a failed `cast_into` doesn't consume
+/// the value the caller passed in. This lets callers retry a different downcast (or
+/// otherwise fall back) without re-fetching the object.
+pub struct PyCastError {
+    pub instance: PyObject,
+    pub error: PythonObjectDowncastError
+}
+
 
-impl <'p> PyObject<'p> {
+impl PyObject {
     /// Creates a PyObject instance for the given FFI pointer.
     /// This moves ownership over the pointer into the PyObject.
     /// Undefined behavior if the pointer is NULL or invalid.
+    /// `py` merely proves that the GIL is held while the reference is created;
+    /// it is not stored.
     #[inline]
-    pub unsafe fn from_owned_ptr(py : Python<'p>, ptr : *mut ffi::PyObject) -> PyObject<'p> {
+    pub unsafe fn from_owned_ptr(_py : Python, ptr : *mut ffi::PyObject) -> PyObject {
         debug_assert!(!ptr.is_null() && ffi::Py_REFCNT(ptr) > 0);
-        PyObject { py: py, ptr: NonZero::new(ptr) }
+        PyObject { ptr: NonZero::new(ptr) }
     }
-    
+
     /// Creates a PyObject instance for the given FFI pointer.
     /// Calls Py_INCREF() on the ptr.
     /// Undefined behavior if the pointer is NULL or invalid.
     #[inline]
-    pub unsafe fn from_borrowed_ptr(py : Python<'p>, ptr : *mut ffi::PyObject) -> PyObject<'p> {
+    pub unsafe fn from_borrowed_ptr(_py : Python, ptr : *mut ffi::PyObject) -> PyObject {
         debug_assert!(!ptr.is_null() && ffi::Py_REFCNT(ptr) > 0);
         ffi::Py_INCREF(ptr);
-        PyObject { py: py, ptr: NonZero::new(ptr) }
+        PyObject { ptr: NonZero::new(ptr) }
     }
 
     /// Creates a PyObject instance for the given FFI pointer.
     /// This moves ownership over the pointer into the PyObject.
     /// Returns None for null pointers; undefined behavior if the pointer is invalid.
     #[inline]
-    pub unsafe fn from_owned_ptr_opt(py: Python<'p>, ptr: *mut ffi::PyObject) -> Option<PyObject<'p>> {
+    pub unsafe fn from_owned_ptr_opt(py: Python, ptr: *mut ffi::PyObject) -> Option<PyObject> {
         if ptr.is_null() {
             None
         } else {
             Some(PyObject::from_owned_ptr(py, ptr))
         }
     }
-    
+
     /// Returns None for null pointers; undefined behavior if the pointer is invalid.
     #[inline]
-    pub unsafe fn from_borrowed_ptr_opt(py: Python<'p>, ptr: *mut ffi::PyObject) -> Option<PyObject<'p>> {
+    pub unsafe fn from_borrowed_ptr_opt(py: Python, ptr: *mut ffi::PyObject) -> Option<PyObject> {
         if ptr.is_null() {
             None
         } else {
             Some(PyObject::from_borrowed_ptr(py, ptr))
         }
     }
-    
+
     /// Transmutes an owned FFI pointer to &PyObject.
     /// Undefined behavior if the pointer is NULL or invalid.
     #[inline]
-    pub unsafe fn borrow_from_owned_ptr<'a>(py : Python<'p>, ptr : &'a *mut ffi::PyObject) -> &'a PyObject<'p> {
+    pub unsafe fn borrow_from_owned_ptr<'a>(ptr : &'a *mut ffi::PyObject) -> &'a PyObject {
         debug_assert!(!ptr.is_null() && ffi::Py_REFCNT(*ptr) > 0);
         transmute(ptr)
     }
-    
+
     /// Transmutes a slice of owned FFI pointers to &[PyObject].
     /// Undefined behavior if the pointer is NULL or invalid.
     #[inline]
-    pub unsafe fn borrow_from_owned_ptr_slice<'a>(py : Python<'p>, ptr : &'a [*mut ffi::PyObject]) -> &'a [PyObject<'p>] {
+    pub unsafe fn borrow_from_owned_ptr_slice<'a>(ptr : &'a [*mut ffi::PyObject]) -> &'a [PyObject] {
         transmute(ptr)
     }
-    
+
+    /// Creates a new reference to the same object, incrementing the refcount.
+    ///
+    /// This is not a `Clone` implementation because cloning requires proof that
+    /// the GIL is held (`Clone::clone()` has no way to accept a `py` token).
+    #[inline]
+    pub fn clone_ref(&self, py: Python) -> PyObject {
+        unsafe { PyObject::from_borrowed_ptr(py, self.as_ptr()) }
+    }
+
+    /// Returns a new reference to Python's `None` singleton.
+    #[inline]
+    pub fn none(py: Python) -> PyObject {
+        unsafe { PyObject::from_borrowed_ptr(py, ffi::Py_None()) }
+    }
+
+    /// Returns whether this object is Python's `None` singleton.
+    #[inline]
+    pub fn is_none(&self) -> bool {
+        self.as_ptr() == unsafe { ffi::Py_None() }
+    }
+
     /// Retrieves the reference count of this python object.
     #[inline]
     pub fn get_refcnt(&self) -> usize {
@@ -156,78 +194,295 @@ impl <'p> PyObject<'p> {
     }
 
     #[inline]
-    pub fn get_type(&self) -> &PyType<'p> {
+    pub fn get_type<'p>(&self, py: Python<'p>) -> &PyType<'p> {
         unsafe {
             let t : &*mut ffi::PyTypeObject = &(*self.as_ptr()).ob_type;
+            let _ = py;
             transmute(t)
         }
     }
-    
+
     /// Casts the PyObject to a concrete python object type.
     /// Causes undefined behavior if the object is not of the expected type.
     /// This is a wrapper function around PythonObject::unchecked_downcast_from().
     #[inline]
-    pub unsafe fn unchecked_cast_into<T>(self) -> T where T: PythonObject<'p> {
+    pub unsafe fn unchecked_cast_into<T>(self) -> T where T: PythonObject {
         PythonObject::unchecked_downcast_from(self)
     }
-    
+
     /// Casts the PyObject to a concrete python object type.
-    /// Returns a python TypeError if the object is not of the expected type.
-    /// This is a wrapper function around PythonObjectWithCheckedDowncast::downcast_from().
+    /// Returns a `PyCastError` if the object is not of the expected type.
+    /// Unlike going through `PythonObjectWithCheckedDowncast::downcast_from` directly,
+    /// a failed cast does not consume `self`: the original object is carried back in
+    /// `PyCastError::instance`, so the caller can recover it and try a different
+    /// downcast without re-fetching it. This works by checking the type via
+    /// `downcast_borrow_from` before consuming `self`, rather than consuming it
+    /// upfront and hoping to get it back.
     #[inline]
-    pub fn cast_into<T>(self) -> Result<T, PythonObjectDowncastError<'p>> where T: PythonObjectWithCheckedDowncast<'p> {
-        PythonObjectWithCheckedDowncast::downcast_from(self)
+    pub fn cast_into<T>(self, py: Python) -> Result<T, PyCastError> where T: PythonObjectWithCheckedDowncast + PythonObject {
+        let _ = py;
+        // Check the type through a borrow first (scoped to this block, so it doesn't
+        // outlive the match below), instead of consuming `self` upfront the way
+        // `downcast_from` does; that's what lets us still move `self` into the error
+        // on a failed cast.
+        let check = {
+            match <T as PythonObjectWithCheckedDowncast>::downcast_borrow_from(&self) {
+                Ok(_) => None,
+                Err(error) => Some(error)
+            }
+        };
+        match check {
+            None => Ok(<T as PythonObject>::unchecked_downcast_from(self)),
+            Some(error) => Err(PyCastError { instance: self, error: error })
+        }
     }
-    
+
     /// Casts the PyObject to a concrete python object type.
     /// Causes undefined behavior if the object is not of the expected type.
     /// This is a wrapper function around PythonObject::unchecked_downcast_borrow_from().
     #[inline]
-    pub unsafe fn unchecked_cast_as<'s, T>(&'s self) -> &'s T where T: PythonObject<'p> {
+    pub unsafe fn unchecked_cast_as<'s, T>(&'s self) -> &'s T where T: PythonObject {
         PythonObject::unchecked_downcast_borrow_from(self)
     }
-    
+
     /// Casts the PyObject to a concrete python object type.
     /// Returns a python TypeError if the object is not of the expected type.
     /// This is a wrapper function around PythonObjectWithCheckedDowncast::downcast_borrow_from().
     #[inline]
-    pub fn cast_as<'s, T>(&'s self) -> Result<&'s T, PythonObjectDowncastError<'p>> where T: PythonObjectWithCheckedDowncast<'p> {
+    pub fn cast_as<'s, T>(&'s self, py: Python) -> Result<&'s T, PythonObjectDowncastError> where T: PythonObjectWithCheckedDowncast {
+        let _ = py;
         PythonObjectWithCheckedDowncast::downcast_borrow_from(self)
     }
-    
+
     /// Extracts some type from the python object.
     /// This is a wrapper function around FromPyObject::from_py_object().
     #[inline]
-    pub fn extract<'s, T>(&'s self) -> Result<T, PyErr<'p>> where T: ::conversion::FromPyObject<'p, 's> {
-        ::conversion::FromPyObject::from_py_object(self)
+    pub fn extract<'p, T>(&self, py: Python<'p>) -> Result<T, PyErr<'p>> where T: ::conversion::FromPyObject<'p> {
+        ::conversion::FromPyObject::from_py_object(py, self)
+    }
+
+    /// Computes this object's hash via the Python object protocol
+    /// (`ffi::PyObject_Hash`).
+    ///
+    /// Panics if the object is unhashable (`PyObject_Hash` returns `-1` with an
+    /// exception set), since `std::hash::Hash::hash` has no way to report an error;
+    /// callers who need to handle unhashable objects should check beforehand instead
+    /// of relying on this method, or go through `HashablePyObject` only for objects
+    /// known to be hashable.
+    pub fn hash(&self, py: Python) -> i64 {
+        let _ = py;
+        let h = unsafe { ffi::PyObject_Hash(self.as_ptr()) };
+        if h == -1 {
+            unsafe { ffi::PyErr_Clear(); }
+            panic!("PyObject::hash() called on an unhashable object");
+        }
+        h as i64
+    }
+
+    /// Compares two objects using Python's rich comparison protocol (`__eq__`),
+    /// rather than pointer identity like `PartialEq::eq` does.
+    ///
+    /// Any exception raised by the comparison is cleared and treated as `false`,
+    /// mirroring `PyObject_RichCompareBool`'s `-1` error return.
+    pub fn rich_eq(&self, py: Python, other: &PyObject) -> bool {
+        let _ = py;
+        let result = unsafe {
+            ffi::PyObject_RichCompareBool(self.as_ptr(), other.as_ptr(), ffi::Py_EQ)
+        };
+        if result == -1 {
+            unsafe { ffi::PyErr_Clear(); }
+            false
+        } else {
+            result == 1
+        }
     }
 }
 
-impl <'p> fmt::String for PyObject<'p> {
+impl fmt::String for PyObject {
     fn fmt(&self, f : &mut fmt::Formatter) -> Result<(),  fmt::Error> {
         use objectprotocol::ObjectProtocol;
-        let repr_obj = try!(self.str().map_err(|_| fmt::Error));
-        let repr = try!(repr_obj.extract::<&str>().map_err(|_| fmt::Error));
+        let gil_guard = Python::acquire_gil();
+        let py = gil_guard.python();
+        let repr_obj = try!(self.str(py).map_err(|_| fmt::Error));
+        let repr = try!(repr_obj.extract::<&str>(py).map_err(|_| fmt::Error));
         f.write_str(repr)
     }
 }
 
-impl <'p> fmt::Show for PyObject<'p> {
+impl fmt::Show for PyObject {
     fn fmt(&self, f : &mut fmt::Formatter) -> Result<(),  fmt::Error> {
         use objectprotocol::ObjectProtocol;
-        let repr_obj = try!(self.repr().map_err(|_| fmt::Error));
-        let repr = try!(repr_obj.extract::<&str>().map_err(|_| fmt::Error));
+        let gil_guard = Python::acquire_gil();
+        let py = gil_guard.python();
+        let repr_obj = try!(self.repr(py).map_err(|_| fmt::Error));
+        let repr = try!(repr_obj.extract::<&str>(py).map_err(|_| fmt::Error));
         f.write_str(repr)
     }
 }
 
-impl <'p> PartialEq for PyObject<'p> {
+impl PartialEq for PyObject {
     #[inline]
-    fn eq(&self, o : &PyObject<'p>) -> bool {
+    fn eq(&self, o : &PyObject) -> bool {
         self.ptr == o.ptr
     }
 }
-impl <'p> Eq for PyObject<'p> { }
+impl Eq for PyObject { }
+
+struct PendingDrops {
+    queue: Mutex<Vec<*mut ffi::PyObject>>
+}
+
+// The queue is only ever touched behind the Mutex, so it's fine to share across threads
+// even though the raw pointers it stores are not Sync on their own.
+unsafe impl Sync for PendingDrops { }
+
+static mut PENDING_DROPS_PTR: *const PendingDrops = 0 as *const PendingDrops;
+static PENDING_DROPS_INIT: Once = ONCE_INIT;
+
+fn pending_drops() -> &'static PendingDrops {
+    unsafe {
+        PENDING_DROPS_INIT.call_once(|| {
+            let b = Box::new(PendingDrops { queue: Mutex::new(Vec::new()) });
+            PENDING_DROPS_PTR = Box::into_raw(b) as *const PendingDrops;
+        });
+        &*PENDING_DROPS_PTR
+    }
+}
+
+/// Queues a decref to happen the next time some thread holds the GIL.
+///
+/// Called from `PyObjectRef`'s `Drop` impl, which cannot assume the GIL is held
+/// at the point it runs.
+fn register_pending_decref(ptr: *mut ffi::PyObject) {
+    pending_drops().queue.lock().unwrap().push(ptr);
+}
+
+/// Flushes all refcount decrements that were deferred because a `PyObjectRef` was
+/// dropped without the GIL held. Must only be called while the GIL is held; `py`
+/// proves that.
+///
+/// Ideally this would run automatically on every GIL acquisition, but the
+/// `GILGuard`/`Python::acquire_gil()` machinery that would hook it in lives in the
+/// `python` module, which isn't part of this chunk. For now it's only called from
+/// `PyObjectRef::into_object`, so a program that moves objects to worker threads via
+/// `PyObjectRef` and drops every one of them there, without ever calling
+/// `into_object`, will leave their decrefs queued indefinitely (a known leak until
+/// the real GIL-acquisition path can call this directly).
+pub fn flush_pending_drops(py: Python) {
+    let _ = py;
+    let pending = ::std::mem::replace(&mut *pending_drops().queue.lock().unwrap(), Vec::new());
+    for ptr in pending.into_iter() {
+        unsafe { ffi::Py_DECREF(ptr); }
+    }
+}
+
+/// A `PyObject` reference that can be sent across threads.
+///
+/// `PyObject` itself is not `Send`: cloning or dropping it touches the Python refcount,
+/// which is only safe while the GIL is held. `PyObjectRef` instead defers the decrement
+/// until `into_object` is next called with the GIL held (see `flush_pending_drops`), so
+/// it can be handed off to a worker thread and recovered there.
+pub struct PyObjectRef {
+    ptr: NonZero<*mut ffi::PyObject>
+}
+
+unsafe impl Send for PyObjectRef { }
+
+impl PyObjectRef {
+    /// Creates a `PyObjectRef` from an owned `PyObject`.
+    ///
+    /// `py` merely proves that construction happens under the GIL; the owned
+    /// reference is simply moved over, no refcount traffic is needed.
+    #[inline]
+    pub fn new(py: Python, obj: PyObject) -> PyObjectRef {
+        let _ = py;
+        PyObjectRef { ptr: unsafe { NonZero::new(obj.steal_ptr()) } }
+    }
+
+    /// Recovers a `PyObject` from this cross-thread reference.
+    ///
+    /// Must be called with the GIL held, as proven by `py`. The returned `PyObject`
+    /// takes over the reference that `new` stashed away. Since we're holding the GIL
+    /// here anyway, this is also a convenient place to flush any decrefs that other
+    /// `PyObjectRef`s deferred while being dropped without it.
+    #[inline]
+    pub fn into_object(self, py: Python) -> PyObject {
+        let ptr = *self.ptr;
+        unsafe { ::std::mem::forget(self); }
+        flush_pending_drops(py);
+        unsafe { PyObject::from_owned_ptr(py, ptr) }
+    }
+}
+
+impl Drop for PyObjectRef {
+    fn drop(&mut self) {
+        // We cannot assume the GIL is held here, so the refcount can't be touched
+        // directly; queue the pointer and let the next GIL holder flush it.
+        register_pending_decref(*self.ptr);
+    }
+}
+
+/// A `PyObject` wrapper with Python value semantics for `Hash`/`PartialEq`/`Eq`,
+/// so it can be used as a key in a Rust `HashMap` or `HashSet`.
+///
+/// `PyObject`'s own `PartialEq` compares pointer identity (`is`); this wrapper
+/// instead hashes and compares via the Python object protocol. The hash is computed
+/// once, under the GIL, at construction time and cached, since `Hash::hash` cannot
+/// take a `py` token itself. As with Python's own dict/set keys, the wrapped object
+/// must not be mutated in a way that would change its hash while it's in use as a key.
+///
+/// `PartialEq::eq` has the same `py`-token problem as `Hash::hash`: it reacquires the
+/// GIL internally via `Python::acquire_gil()` rather than taking one as a parameter.
+/// That means equality checks driven from inside a `HashMap`/`HashSet` operation that
+/// is itself already running under the GIL (the overwhelmingly common case) depend on
+/// `Python::acquire_gil()` being safe to call reentrantly from a thread that already
+/// holds it, rather than blocking or deadlocking. That's the same assumption `Drop for
+/// PyObject` and the `fmt::String`/`fmt::Show` impls in this file already make, but
+/// the `GILGuard`/`acquire_gil` implementation lives in the `python` module, which
+/// isn't part of this chunk, so this reentrancy guarantee can't be verified here.
+pub struct HashablePyObject {
+    obj: PyObject,
+    hash: i64
+}
+
+impl HashablePyObject {
+    /// Wraps `obj`, computing and caching its hash under the GIL.
+    ///
+    /// Panics if `obj` is unhashable; see `PyObject::hash`.
+    pub fn new(py: Python, obj: PyObject) -> HashablePyObject {
+        let hash = obj.hash(py);
+        HashablePyObject { obj: obj, hash: hash }
+    }
+
+    #[inline]
+    pub fn as_object(&self) -> &PyObject {
+        &self.obj
+    }
+
+    #[inline]
+    pub fn into_object(self) -> PyObject {
+        self.obj
+    }
+}
+
+impl Hash for HashablePyObject {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.hash.hash(state);
+    }
+}
+
+impl PartialEq for HashablePyObject {
+    fn eq(&self, other: &HashablePyObject) -> bool {
+        // Cheap rejection first: unequal caches can only happen for unequal objects.
+        if self.hash != other.hash {
+            return false;
+        }
+        let gil_guard = Python::acquire_gil();
+        let py = gil_guard.python();
+        self.obj.rich_eq(py, &other.obj)
+    }
+}
+impl Eq for HashablePyObject { }
 
 
 #[test]